@@ -0,0 +1,321 @@
+//! This module provides the functionality needed to convert diagnostics from
+//! `cargo check` json format to the LSP diagnostic format.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use cargo_metadata::diagnostic::{
+    Applicability, Diagnostic as RaDiagnostic, DiagnosticLevel, DiagnosticSpan,
+    DiagnosticSpanMacroExpansion,
+};
+use lsp_types::{
+    CodeAction, Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, Location,
+    NumberOrString, Position, Range, TextEdit, Url, WorkspaceEdit,
+};
+
+/// A cargo diagnostic mapped to its LSP counterpart, along with any
+/// quick-fixes `rustc`/`clippy` suggested for it.
+#[derive(Debug)]
+pub(crate) struct MappedRustDiagnostic {
+    pub(crate) location: Location,
+    pub(crate) diagnostic: Diagnostic,
+    pub(crate) fixes: Vec<CodeAction>,
+}
+
+fn map_level_to_severity(level: DiagnosticLevel) -> Option<DiagnosticSeverity> {
+    match level {
+        DiagnosticLevel::Ice => Some(DiagnosticSeverity::Error),
+        DiagnosticLevel::Error => Some(DiagnosticSeverity::Error),
+        DiagnosticLevel::Warning => Some(DiagnosticSeverity::Warning),
+        DiagnosticLevel::Note => Some(DiagnosticSeverity::Information),
+        DiagnosticLevel::Help => Some(DiagnosticSeverity::Hint),
+        DiagnosticLevel::Unknown => None,
+    }
+}
+
+fn map_span_to_location(span: &DiagnosticSpan, workspace_root: &Path) -> Location {
+    let file_name = workspace_root.join(&span.file_name);
+    let uri = Url::from_file_path(file_name).unwrap();
+
+    let range = Range::new(
+        Position::new(span.line_start as u64 - 1, span.column_start as u64 - 1),
+        Position::new(span.line_end as u64 - 1, span.column_end as u64 - 1),
+    );
+
+    Location::new(uri, range)
+}
+
+fn map_secondary_span_to_related(
+    span: &DiagnosticSpan,
+    workspace_root: &Path,
+) -> DiagnosticRelatedInformation {
+    DiagnosticRelatedInformation {
+        location: map_span_to_location(span, workspace_root),
+        message: span.label.clone().unwrap_or_default(),
+    }
+}
+
+/// Recursively walks the macro expansion chain of a span, so that a
+/// diagnostic produced inside a macro expansion also points back at the
+/// macro use site.
+fn push_macro_expansion_related(
+    expansion: &DiagnosticSpanMacroExpansion,
+    workspace_root: &Path,
+    related_information: &mut Vec<DiagnosticRelatedInformation>,
+) {
+    related_information.push(DiagnosticRelatedInformation {
+        location: map_span_to_location(&expansion.span, workspace_root),
+        message: "Error originated from macro call here".to_string(),
+    });
+    if let Some(expansion) = &expansion.span.expansion {
+        push_macro_expansion_related(expansion, workspace_root, related_information);
+    }
+}
+
+/// Converts a Rust child diagnostic (a "note" or "help" attached to the
+/// primary diagnostic) into LSP related information.
+fn map_rust_child_diagnostic(
+    rd: &RaDiagnostic,
+    workspace_root: &Path,
+) -> Option<DiagnosticRelatedInformation> {
+    let span = rd.spans.iter().find(|s| s.is_primary)?;
+    Some(DiagnosticRelatedInformation {
+        location: map_span_to_location(span, workspace_root),
+        message: rd.message.clone(),
+    })
+}
+
+/// Turns a child "help" diagnostic's suggested replacements into a single
+/// quick-fix code action covering *all* of its spans, since a multi-part
+/// suggestion (f.x. swapping two expressions, or adding an import while
+/// renaming a usage) only produces valid code when every span is applied
+/// together. The fix is only marked as auto-applicable when every one of
+/// its spans is `Applicability::MachineApplicable`.
+fn map_suggestion_to_fix(child: &RaDiagnostic, workspace_root: &Path) -> Option<CodeAction> {
+    let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+    let mut all_machine_applicable = true;
+
+    for span in &child.spans {
+        let suggested_replacement = match &span.suggested_replacement {
+            Some(replacement) => replacement,
+            None => continue,
+        };
+
+        if span.suggestion_applicability != Some(Applicability::MachineApplicable) {
+            all_machine_applicable = false;
+        }
+
+        let location = map_span_to_location(span, workspace_root);
+        let edit = TextEdit { range: location.range, new_text: suggested_replacement.clone() };
+        changes.entry(location.uri).or_default().push(edit);
+    }
+
+    if changes.is_empty() {
+        return None;
+    }
+
+    Some(CodeAction {
+        title: child.message.clone(),
+        kind: Some("quickfix".to_string()),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit { changes: Some(changes), document_changes: None }),
+        command: None,
+        is_preferred: Some(all_machine_applicable),
+    })
+}
+
+/// Converts a rust diagnostic, as emitted by `cargo check --message-format=json`,
+/// into its LSP `Diagnostic` plus any quick-fix `CodeAction`s it suggests.
+///
+/// Returns `None` if the diagnostic has no primary span, f.x. some
+/// workspace-wide warnings emitted by cargo itself.
+pub(crate) fn map_rust_diagnostic_to_lsp(
+    rd: &RaDiagnostic,
+    workspace_root: &Path,
+) -> Option<MappedRustDiagnostic> {
+    let primary_span = rd.spans.iter().find(|s| s.is_primary)?;
+    let location = map_span_to_location(primary_span, workspace_root);
+
+    let mut related_information = Vec::new();
+    for span in rd.spans.iter().filter(|s| !s.is_primary) {
+        related_information.push(map_secondary_span_to_related(span, workspace_root));
+    }
+    for span in &rd.spans {
+        if let Some(expansion) = &span.expansion {
+            push_macro_expansion_related(expansion, workspace_root, &mut related_information);
+        }
+    }
+    for child in &rd.children {
+        if let Some(related) = map_rust_child_diagnostic(child, workspace_root) {
+            related_information.push(related);
+        }
+    }
+
+    let diagnostic = Diagnostic {
+        range: location.range,
+        severity: map_level_to_severity(rd.level),
+        code: rd.code.as_ref().map(|c| NumberOrString::String(c.code.clone())),
+        source: Some("rustc".to_string()),
+        message: rd.message.clone(),
+        related_information: if related_information.is_empty() {
+            None
+        } else {
+            Some(related_information)
+        },
+        tags: None,
+    };
+
+    // `rustc`/`clippy` attach the actual suggested replacement to the spans of
+    // the child "help" diagnostics, not to the primary diagnostic's own spans,
+    // so each fix (and its title) comes from a child rather than from `rd` itself.
+    let fixes =
+        rd.children.iter().filter_map(|child| map_suggestion_to_fix(child, workspace_root)).collect();
+
+    Some(MappedRustDiagnostic { location, diagnostic, fixes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cargo_metadata::diagnostic::DiagnosticSpanLine;
+    use std::path::PathBuf;
+
+    fn workspace_root() -> PathBuf {
+        PathBuf::from(if cfg!(windows) { r"C:\ws" } else { "/ws" })
+    }
+
+    fn span(
+        file_name: &str,
+        suggested_replacement: Option<&str>,
+        applicability: Option<Applicability>,
+    ) -> DiagnosticSpan {
+        DiagnosticSpan {
+            file_name: file_name.to_string(),
+            byte_start: 0,
+            byte_end: 1,
+            line_start: 1,
+            line_end: 1,
+            column_start: 1,
+            column_end: 2,
+            is_primary: true,
+            text: vec![DiagnosticSpanLine {
+                text: "fn main() {}".to_string(),
+                highlight_start: 1,
+                highlight_end: 2,
+            }],
+            label: None,
+            suggested_replacement: suggested_replacement.map(|s| s.to_string()),
+            suggestion_applicability: applicability,
+            expansion: None,
+        }
+    }
+
+    fn child(message: &str, spans: Vec<DiagnosticSpan>) -> RaDiagnostic {
+        RaDiagnostic {
+            message: message.to_string(),
+            code: None,
+            level: DiagnosticLevel::Help,
+            spans,
+            children: vec![],
+            rendered: None,
+        }
+    }
+
+    #[test]
+    fn machine_applicable_suggestion_is_preferred() {
+        let fix = map_suggestion_to_fix(
+            &child(
+                "change this",
+                vec![span("src/main.rs", Some("replacement"), Some(Applicability::MachineApplicable))],
+            ),
+            &workspace_root(),
+        )
+        .unwrap();
+        assert_eq!(fix.is_preferred, Some(true));
+    }
+
+    #[test]
+    fn non_machine_applicable_suggestion_is_not_preferred() {
+        let fix = map_suggestion_to_fix(
+            &child(
+                "change this",
+                vec![span("src/main.rs", Some("replacement"), Some(Applicability::MaybeIncorrect))],
+            ),
+            &workspace_root(),
+        )
+        .unwrap();
+        assert_eq!(fix.is_preferred, Some(false));
+    }
+
+    #[test]
+    fn child_without_any_suggested_replacement_yields_no_fix() {
+        let c = child("change this", vec![span("src/main.rs", None, None)]);
+        assert!(map_suggestion_to_fix(&c, &workspace_root()).is_none());
+    }
+
+    #[test]
+    fn fixes_come_from_child_spans_not_the_primary_diagnostic() {
+        // The primary span never carries a suggestion in real rustc/clippy
+        // output; the replacement lives on the spans of a child "help"
+        // diagnostic instead.
+        let primary_span = span("src/main.rs", None, None);
+        let help_span =
+            span("src/main.rs", Some("use foo::Bar;"), Some(Applicability::MachineApplicable));
+
+        let rd = RaDiagnostic {
+            message: "unresolved import `Bar`".to_string(),
+            code: None,
+            level: DiagnosticLevel::Error,
+            spans: vec![primary_span],
+            children: vec![child("consider importing this type", vec![help_span])],
+            rendered: None,
+        };
+
+        let mapped = map_rust_diagnostic_to_lsp(&rd, &workspace_root()).unwrap();
+        assert_eq!(mapped.fixes.len(), 1);
+        assert_eq!(mapped.fixes[0].title, "consider importing this type");
+    }
+
+    #[test]
+    fn multi_span_child_becomes_a_single_code_action_with_all_edits() {
+        // A multi-part suggestion (f.x. renaming a binding at its declaration
+        // and at every usage) only produces valid code when every span is
+        // applied together, so it must stay a single `CodeAction`.
+        let rd = RaDiagnostic {
+            message: "cannot find value `foo` in this scope".to_string(),
+            code: None,
+            level: DiagnosticLevel::Error,
+            spans: vec![span("src/main.rs", None, None)],
+            children: vec![child(
+                "a local variable with a similar name exists",
+                vec![
+                    span("src/main.rs", Some("foo_bar"), Some(Applicability::MachineApplicable)),
+                    span("src/main.rs", Some("foo_bar"), Some(Applicability::MachineApplicable)),
+                ],
+            )],
+            rendered: None,
+        };
+
+        let mapped = map_rust_diagnostic_to_lsp(&rd, &workspace_root()).unwrap();
+        assert_eq!(mapped.fixes.len(), 1);
+        let changes = mapped.fixes[0].edit.as_ref().unwrap().changes.as_ref().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes.values().next().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn multi_span_child_is_not_preferred_unless_every_span_is_machine_applicable() {
+        let fix = map_suggestion_to_fix(
+            &child(
+                "change this",
+                vec![
+                    span("src/main.rs", Some("a"), Some(Applicability::MachineApplicable)),
+                    span("src/main.rs", Some("b"), Some(Applicability::MaybeIncorrect)),
+                ],
+            ),
+            &workspace_root(),
+        )
+        .unwrap();
+        assert_eq!(fix.is_preferred, Some(false));
+    }
+}
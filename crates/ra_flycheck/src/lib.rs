@@ -2,20 +2,20 @@
 //! another compatible command (f.x. clippy) in a background thread and provide
 //! LSP diagnostics based on the output of the command.
 
+mod conv;
+
 use std::{
     fmt,
-    io::{self, BufReader},
+    io::{BufRead, BufReader},
     path::PathBuf,
-    process::{Command, Stdio},
-    time::Instant,
+    process::{Child, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use cargo_metadata::Message;
-use crossbeam_channel::{never, select, unbounded, Receiver, RecvError, Sender};
-
-pub use cargo_metadata::diagnostic::{
-    Applicability, Diagnostic, DiagnosticLevel, DiagnosticSpan, DiagnosticSpanMacroExpansion,
-};
+use crossbeam_channel::{after, never, select, unbounded, Receiver, RecvError, Sender};
+use lsp_types::{CodeAction, Diagnostic, Location};
 
 type Progress = ra_progress::Progress<(), String>;
 type ProgressSource = ra_progress::ProgressSource<(), String>;
@@ -28,18 +28,33 @@ pub enum FlycheckConfig {
         all_features: bool,
         features: Vec<String>,
         extra_args: Vec<String>,
+        /// Delay to wait for additional changes before triggering a check,
+        /// so that f.x. on-keystroke checks don't spawn a process per keystroke
+        debounce: Duration,
     },
     CustomCommand {
         command: String,
         args: Vec<String>,
+        /// Delay to wait for additional changes before triggering a check,
+        /// so that f.x. on-keystroke checks don't spawn a process per keystroke
+        debounce: Duration,
     },
 }
 
+impl FlycheckConfig {
+    fn debounce(&self) -> Duration {
+        match self {
+            FlycheckConfig::CargoCommand { debounce, .. }
+            | FlycheckConfig::CustomCommand { debounce, .. } => *debounce,
+        }
+    }
+}
+
 impl fmt::Display for FlycheckConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             FlycheckConfig::CargoCommand { command, .. } => write!(f, "cargo {}", command),
-            FlycheckConfig::CustomCommand { command, args } => {
+            FlycheckConfig::CustomCommand { command, args, .. } => {
                 write!(f, "{} {}", command, args.join(" "))
             }
         }
@@ -76,6 +91,12 @@ impl Flycheck {
     pub fn update(&self) {
         self.cmd_send.send(CheckCommand::Update).unwrap();
     }
+
+    /// Stop a currently running cargo check invocation, without scheduling a
+    /// new one.
+    pub fn cancel(&self) {
+        self.cmd_send.send(CheckCommand::Cancel).unwrap();
+    }
 }
 
 #[derive(Debug)]
@@ -84,12 +105,19 @@ pub enum CheckTask {
     ClearDiagnostics,
 
     /// Request adding a diagnostic with fixes included to a file
-    AddDiagnostic { workspace_root: PathBuf, diagnostic: Diagnostic },
+    AddDiagnostic { location: Location, diagnostic: Diagnostic, fixes: Vec<CodeAction> },
+
+    /// Request that a user-visible error is shown, f.x. because the check
+    /// command was misconfigured or failed to produce any usable output
+    Error { message: String },
 }
 
 pub enum CheckCommand {
     /// Request re-start of check thread
     Update,
+
+    /// Kill the currently running check process, without scheduling a new one
+    Cancel,
 }
 
 struct FlycheckThread {
@@ -99,7 +127,11 @@ struct FlycheckThread {
     progress_src: ProgressSource,
     progress: Option<Progress>,
     // XXX: drop order is significant
-    message_recv: Receiver<CheckEvent>,
+    message_recv: Receiver<Result<CheckEvent, CargoError>>,
+    /// Handle to the currently running check process, if any, shared with the
+    /// `check_process` thread so that `cancel_check_process` can kill it from
+    /// here without waiting for the process to notice its channel was closed.
+    child: Arc<Mutex<Option<Child>>>,
     /// WatchThread exists to wrap around the communication needed to be able to
     /// run `cargo check` without blocking. Currently the Rust standard library
     /// doesn't provide a way to read sub-process output without blocking, so we
@@ -121,6 +153,7 @@ impl FlycheckThread {
             last_update_req: None,
             progress: None,
             message_recv: never(),
+            child: Arc::new(Mutex::new(None)),
             check_process: None,
         }
     }
@@ -130,6 +163,15 @@ impl FlycheckThread {
         self.clean_previous_results(task_send);
 
         loop {
+            // Only wait for the debounce timeout while there is a pending
+            // update request; otherwise never fire, so we don't busy-loop.
+            let debounce_timeout = match self.last_update_req {
+                Some(last_update_req) => {
+                    after(self.config.debounce().saturating_sub(last_update_req.elapsed()))
+                }
+                None => never(),
+            };
+
             select! {
                 recv(&cmd_recv) -> cmd => match cmd {
                     Ok(cmd) => self.handle_command(cmd),
@@ -139,21 +181,26 @@ impl FlycheckThread {
                     },
                 },
                 recv(self.message_recv) -> msg => match msg {
-                    Ok(msg) => self.handle_message(msg, task_send),
+                    Ok(Ok(msg)) => self.handle_message(msg, task_send),
+                    Ok(Err(err)) => {
+                        self.progress = None;
+                        task_send.send(CheckTask::Error { message: err.to_string() }).unwrap();
+                    }
                     Err(RecvError) => {
                         // Watcher finished, replace it with a never channel to
                         // avoid busy-waiting.
                         self.message_recv = never();
                         self.check_process = None;
                     },
+                },
+                recv(debounce_timeout) -> _ => {
+                    // No newer update request arrived while we were waiting
+                    // out the debounce, so it's time to actually recheck.
+                    self.last_update_req = None;
+                    task_send.send(CheckTask::ClearDiagnostics).unwrap();
+                    self.restart_check_process();
                 }
             };
-
-            if self.should_recheck() {
-                self.last_update_req = None;
-                task_send.send(CheckTask::ClearDiagnostics).unwrap();
-                self.restart_check_process();
-            }
         }
     }
 
@@ -162,22 +209,33 @@ impl FlycheckThread {
         self.progress = None;
     }
 
-    fn should_recheck(&mut self) -> bool {
-        if let Some(_last_update_req) = &self.last_update_req {
-            // We currently only request an update on save, as we need up to
-            // date source on disk for cargo check to do it's magic, so we
-            // don't really need to debounce the requests at this point.
-            return true;
-        }
-        false
-    }
-
     fn handle_command(&mut self, cmd: CheckCommand) {
         match cmd {
             CheckCommand::Update => self.last_update_req = Some(Instant::now()),
+            CheckCommand::Cancel => self.cancel_check_process(),
         }
     }
 
+    /// Kill the currently running check process (if any) and forget about
+    /// it, without arming a new one; unlike `restart_check_process`, this
+    /// does not spawn a replacement.
+    fn cancel_check_process(&mut self) {
+        self.last_update_req = None;
+        if let Some(mut child) = self.child.lock().unwrap().take() {
+            let _ = child.kill();
+            // Reap the process on a detached thread instead of waiting here,
+            // so that cancelling doesn't block the select loop; `Child`
+            // doesn't reap itself on drop, so skipping this would leak a
+            // zombie process.
+            std::thread::spawn(move || {
+                let _ = child.wait();
+            });
+        }
+        self.message_recv = never();
+        self.check_process = None;
+        self.progress = None;
+    }
+
     fn handle_message(&mut self, msg: CheckEvent, task_send: &Sender<CheckTask>) {
         match msg {
             CheckEvent::Begin => {
@@ -192,12 +250,17 @@ impl FlycheckThread {
             }
 
             CheckEvent::Msg(Message::CompilerMessage(msg)) => {
-                task_send
-                    .send(CheckTask::AddDiagnostic {
-                        workspace_root: self.workspace_root.clone(),
-                        diagnostic: msg.message,
-                    })
-                    .unwrap();
+                if let Some(mapped) =
+                    conv::map_rust_diagnostic_to_lsp(&msg.message, &self.workspace_root)
+                {
+                    task_send
+                        .send(CheckTask::AddDiagnostic {
+                            location: mapped.location,
+                            diagnostic: mapped.diagnostic,
+                            fixes: mapped.fixes,
+                        })
+                        .unwrap();
+                }
             }
 
             CheckEvent::Msg(Message::BuildScriptExecuted(_msg)) => {}
@@ -219,6 +282,7 @@ impl FlycheckThread {
                 all_features,
                 extra_args,
                 features,
+                ..
             } => {
                 let mut cmd = Command::new(ra_toolchain::cargo());
                 cmd.arg(command);
@@ -236,7 +300,7 @@ impl FlycheckThread {
                 cmd.args(extra_args);
                 cmd
             }
-            FlycheckConfig::CustomCommand { command, args } => {
+            FlycheckConfig::CustomCommand { command, args, .. } => {
                 let mut cmd = Command::new(command);
                 cmd.args(args);
                 cmd
@@ -246,12 +310,14 @@ impl FlycheckThread {
 
         let (message_send, message_recv) = unbounded();
         self.message_recv = message_recv;
+        let child = Arc::new(Mutex::new(None));
+        self.child = Arc::clone(&child);
         self.check_process = Some(jod_thread::spawn(move || {
             // If we trigger an error here, we will do so in the loop instead,
             // which will break out of the loop, and continue the shutdown
-            let _ = message_send.send(CheckEvent::Begin);
+            let _ = message_send.send(Ok(CheckEvent::Begin));
 
-            let res = run_cargo(cmd, &mut |message| {
+            let res = run_cargo(cmd, &child, &mut |message| {
                 // Skip certain kinds of messages to only spend time on what's useful
                 match &message {
                     Message::CompilerArtifact(artifact) if artifact.fresh => return true,
@@ -261,18 +327,18 @@ impl FlycheckThread {
                 }
 
                 // if the send channel was closed, we want to shutdown
-                message_send.send(CheckEvent::Msg(message)).is_ok()
+                message_send.send(Ok(CheckEvent::Msg(message))).is_ok()
             });
 
             if let Err(err) = res {
-                // FIXME: make the `message_send` to be `Sender<Result<CheckEvent, CargoError>>`
-                // to display user-caused misconfiguration errors instead of just logging them here
-                log::error!("Cargo watcher failed {:?}", err);
+                // Surface the failure to the user instead of only logging it,
+                // since it usually means the check command is misconfigured
+                let _ = message_send.send(Err(err));
             }
 
             // We can ignore any error here, as we are already in the progress
             // of shutting down.
-            let _ = message_send.send(CheckEvent::End);
+            let _ = message_send.send(Ok(CheckEvent::End));
         }))
     }
 }
@@ -283,12 +349,44 @@ enum CheckEvent {
     End,
 }
 
+/// Something went wrong while invoking the check command itself, as opposed
+/// to the command running successfully and reporting compiler diagnostics.
+#[derive(Debug)]
+struct CargoError(String);
+
+impl fmt::Display for CargoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for CargoError {}
+
 fn run_cargo(
     mut command: Command,
+    child_slot: &Mutex<Option<Child>>,
     on_message: &mut dyn FnMut(cargo_metadata::Message) -> bool,
-) -> io::Result<()> {
-    let mut child =
-        command.stdout(Stdio::piped()).stderr(Stdio::null()).stdin(Stdio::null()).spawn()?;
+) -> Result<(), CargoError> {
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|err| CargoError(format!("failed to spawn {:?}: {}", command, err)))?;
+
+    // Capture stderr on a side thread so we can include it in the error
+    // message if the command turns out to have failed; we can't just read it
+    // after `wait()`, as the child could block forever trying to write to a
+    // full pipe while we are not reading from it.
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+    let stderr_handle = jod_thread::spawn(move || {
+        let mut buf = String::new();
+        for line in stderr.lines().filter_map(Result::ok) {
+            buf.push_str(&line);
+            buf.push('\n');
+        }
+        buf
+    });
 
     // We manually read a line at a time, instead of using serde's
     // stream deserializers, because the deserializer cannot recover
@@ -299,6 +397,11 @@ fn run_cargo(
     // simply skip a line if it doesn't parse, which just ignores any
     // erroneus output.
     let stdout = BufReader::new(child.stdout.take().unwrap());
+
+    // Hand the child over to the shared slot so `Flycheck::cancel` can kill
+    // it from the outside; we reclaim it below once we're done reading.
+    *child_slot.lock().unwrap() = Some(child);
+
     let mut read_at_least_one_message = false;
     for message in cargo_metadata::Message::parse_stream(stdout) {
         let message = match message {
@@ -316,20 +419,26 @@ fn run_cargo(
         }
     }
 
+    // Reclaim the child from the shared slot; if it's already gone, `cancel`
+    // beat us to it: it killed the child and handed it to a thread of its own
+    // to reap, so there's nothing left to report.
+    let mut child = match child_slot.lock().unwrap().take() {
+        Some(child) => child,
+        None => return Ok(()),
+    };
+
     // It is okay to ignore the result, as it only errors if the process is already dead
     let _ = child.kill();
 
-    let exit_status = child.wait()?;
+    let exit_status =
+        child.wait().map_err(|err| CargoError(format!("failed to wait for {:?}: {}", command, err)))?;
+    let stderr = stderr_handle.join();
+
     if !exit_status.success() && !read_at_least_one_message {
-        // FIXME: Read the stderr to display the reason, see `read2()` reference in PR comment:
-        // https://github.com/rust-analyzer/rust-analyzer/pull/3632#discussion_r395605298
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!(
-                "the command produced no valid metadata (exit code: {:?}): {:?}",
-                exit_status, command
-            ),
-        ));
+        return Err(CargoError(format!(
+            "the command produced no valid metadata (exit code: {:?}):\n{}\ncommand: {:?}",
+            exit_status, stderr, command
+        )));
     }
 
     Ok(())